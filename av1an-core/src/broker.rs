@@ -2,71 +2,465 @@ use crate::{
   ffmpeg, finish_multi_progress_bar, finish_progress_bar, get_done, settings::EncodeArgs, Chunk,
   Instant, TargetQuality, Verbosity,
 };
-use std::{fs::File, io::Write, path::Path, sync::mpsc::Sender};
+use std::{
+  collections::BTreeSet,
+  fs::{self, File},
+  io::Write,
+  path::{Path, PathBuf},
+  process::{Command, ExitStatus},
+  sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    mpsc::Sender,
+    Arc, Condvar, Mutex,
+  },
+  time::Duration,
+};
 
+use crossbeam_channel::{Receiver, RecvTimeoutError};
+use dashmap::DashMap;
 use nix::sched::{sched_setaffinity, CpuSet};
 use nix::unistd::Pid;
+use serde::Serialize;
 
-pub struct Broker<'a> {
-  pub chunk_queue: Vec<Chunk>,
-  pub project: &'a EncodeArgs,
-  pub target_quality: Option<TargetQuality<'a>>,
+/// Default for `EncodeArgs::max_chunk_retries`: the number of failed attempts
+/// after which a single chunk is quarantined and reported as a failure at the
+/// end of the run, rather than aborting the whole encode. Every earlier
+/// failure re-queues the chunk.
+pub const DEFAULT_MAX_CHUNK_RETRIES: u8 = 8;
+
+/// Commands accepted by a running [`Broker::encoding_loop`], sent over the
+/// `cmd_rx` channel passed in by the caller (e.g. a TUI or remote control
+/// interface). Each command is taken off the channel by whichever worker
+/// drains it first, but it is applied to state shared by the whole pool, so
+/// it affects every worker.
+#[derive(Debug, Clone, Copy)]
+pub enum BrokerCommand {
+  /// Park all workers in between chunks until a `Resume` is received.
+  Pause,
+  /// Wake workers parked by a previous `Pause`.
+  Resume,
+  /// Stop pulling new chunks from the queue and let in-flight chunks finish,
+  /// leaving `done.json` in a resumable state.
+  Cancel,
+  /// Change the number of workers that are actively pulling from the queue,
+  /// clamped to the range `1..=Broker::project.workers`.
+  SetWorkers(usize),
 }
 
-impl<'a> Broker<'a> {
-  pub fn new(
-    chunk_queue: Vec<Chunk>,
-    project: &'a EncodeArgs,
-    target_quality: Option<TargetQuality<'a>>,
-  ) -> Self {
-    Broker {
-      chunk_queue,
-      project,
-      target_quality,
+/// Shared state that lets the worker pool react to [`BrokerCommand`]s
+/// without tearing down and respawning threads for every pause/resume or
+/// worker-count change.
+struct RunControl {
+  paused: Mutex<bool>,
+  pause_changed: Condvar,
+  cancelled: AtomicBool,
+  active_workers: AtomicUsize,
+}
+
+impl RunControl {
+  fn new(workers: usize) -> Self {
+    Self {
+      paused: Mutex::new(false),
+      pause_changed: Condvar::new(),
+      cancelled: AtomicBool::new(false),
+      active_workers: AtomicUsize::new(workers),
     }
   }
 
-  #[allow(clippy::needless_pass_by_value)]
-  pub fn encoding_loop(self, tx: Sender<()>) {
-    if !self.chunk_queue.is_empty() {
-      let (sender, receiver) = crossbeam_channel::bounded(self.chunk_queue.len());
+  /// Drains every pending command, applying the latest state for each kind.
+  fn apply_pending(&self, cmd_rx: &Receiver<BrokerCommand>, max_workers: usize) {
+    while let Ok(cmd) = cmd_rx.try_recv() {
+      match cmd {
+        BrokerCommand::Pause => {
+          *self.paused.lock().unwrap() = true;
+        }
+        BrokerCommand::Resume => {
+          *self.paused.lock().unwrap() = false;
+          self.pause_changed.notify_all();
+        }
+        BrokerCommand::Cancel => {
+          self.cancelled.store(true, Ordering::SeqCst);
+          *self.paused.lock().unwrap() = false;
+          self.pause_changed.notify_all();
+        }
+        BrokerCommand::SetWorkers(n) => {
+          self.active_workers.store(n.clamp(1, max_workers), Ordering::SeqCst);
+          self.pause_changed.notify_all();
+        }
+      }
+    }
+  }
+
+  fn is_cancelled(&self) -> bool {
+    self.cancelled.load(Ordering::SeqCst)
+  }
+
+  /// Blocks the calling worker while the run is paused or this worker has
+  /// been retired by a `SetWorkers` below its index, waking early if the run
+  /// is cancelled.
+  ///
+  /// Keeps draining `cmd_rx` itself on every wake (rather than relying on
+  /// the outer worker loop, which this thread isn't returning to) so a
+  /// subsequent `Resume`/`Cancel`/`SetWorkers` is never stranded behind every
+  /// worker having converged here at once during a full pause.
+  fn park_if_inactive(&self, worker_idx: usize, cmd_rx: &Receiver<BrokerCommand>, max_workers: usize) {
+    loop {
+      self.apply_pending(cmd_rx, max_workers);
 
-      for chunk in &self.chunk_queue {
-        sender.send(chunk.clone()).unwrap();
+      if self.is_cancelled() {
+        return;
       }
-      drop(sender);
-
-      crossbeam_utils::thread::scope(|s| {
-        let consumers: Vec<_> = (0..self.project.workers)
-          .map(|i| (receiver.clone(), &self, i))
-          .map(|(rx, queue, consumer_idx)| {
-            let tx = tx.clone();
-            s.spawn(move |_| {
-              while let Ok(mut chunk) = rx.recv() {
-                if queue.encode_chunk(&mut chunk, consumer_idx).is_err() {
-                  tx.send(()).unwrap();
-                  return Err(());
-                }
-              }
-              Ok(())
-            })
-          })
-          .collect();
-        for consumer in consumers {
-          let _ = consumer.join().unwrap();
-        }
-      })
-      .unwrap();
 
-      if self.project.verbosity == Verbosity::Normal {
-        finish_progress_bar();
-      } else if self.project.verbosity == Verbosity::Verbose {
-        finish_multi_progress_bar();
+      let paused = self.paused.lock().unwrap();
+      let active = worker_idx < self.active_workers.load(Ordering::SeqCst);
+      if active && !*paused {
+        return;
       }
+      let _ = self.pause_changed.wait_timeout(paused, Duration::from_millis(100)).unwrap();
     }
   }
+}
 
-  fn encode_chunk(&self, chunk: &mut Chunk, worker_id: usize) -> Result<(), String> {
+/// Per-chunk lifecycle events, published over [`EventBus`] as the broker
+/// works through the queue. This replaces the `info!`/`warn!` calls that
+/// used to be the only way to observe progress, and lets external monitors
+/// (TUIs, web dashboards) subscribe without coupling to the encode core.
+#[derive(Debug, Clone, Serialize)]
+pub enum ChunkEvent {
+  Started { index: usize, frames: usize },
+  PassDone { index: usize, pass: usize },
+  Completed {
+    index: usize,
+    name: String,
+    output: PathBuf,
+    encoded_frames: usize,
+    fps: f64,
+    elapsed_secs: f64,
+  },
+  FrameMismatch { index: usize, expected: usize, actual: usize },
+  Failed { index: usize, attempt: u8 },
+  Requeued { index: usize },
+  /// The chunk reached `max_chunk_retries` failures and was given up on; it will
+  /// never produce a `Completed` event.
+  Quarantined { index: usize },
+}
+
+/// A fan-out broadcast channel: every subscriber receives every published
+/// event, independent of the others. `Broker` owns one of these and publishes
+/// chunk lifecycle events to it, so the NDJSON event log, the done-state
+/// persistence and the live output are built-in subscribers rather than
+/// special-cased call sites. Retries and quarantines are also logged
+/// directly with `warn!`/`error!`.
+struct EventBus {
+  subscribers: Mutex<Vec<crossbeam_channel::Sender<ChunkEvent>>>,
+}
+
+impl EventBus {
+  fn new() -> Self {
+    Self {
+      subscribers: Mutex::new(Vec::new()),
+    }
+  }
+
+  fn subscribe(&self) -> Receiver<ChunkEvent> {
+    let (tx, rx) = crossbeam_channel::unbounded();
+    self.subscribers.lock().unwrap().push(tx);
+    rx
+  }
+
+  fn publish(&self, event: ChunkEvent) {
+    for subscriber in self.subscribers.lock().unwrap().iter() {
+      let _ = subscriber.send(event.clone());
+    }
+  }
+
+  /// Drops every subscriber's sender so their `recv()` loops see
+  /// `Disconnected` and exit, once the broker is done publishing.
+  fn close(&self) {
+    self.subscribers.lock().unwrap().clear();
+  }
+}
+
+/// Built-in subscriber: appends each event as a newline-delimited JSON
+/// record, so an external monitor can `tail -f` the run.
+///
+/// A transient IO error here (disk full, temp dir removed) just drops that
+/// line rather than panicking the thread: `encoding_loop` joins this thread
+/// at the end of the run, so a panic here would tear down an otherwise
+/// successful encode over nothing but a logging failure. If the log file
+/// cannot be opened at all, a warning is logged and no events are recorded.
+fn ndjson_log_subscriber(rx: Receiver<ChunkEvent>, path: &Path) {
+  let mut file = match File::options().create(true).append(true).open(path) {
+    Ok(file) => file,
+    Err(e) => {
+      warn!("Event log: failed to open {}: {e}", path.display());
+      return;
+    }
+  };
+  while let Ok(event) = rx.recv() {
+    if let Ok(line) = serde_json::to_string(&event) {
+      if let Err(e) = writeln!(file, "{line}") {
+        warn!("Event log: failed to write to {}: {e}", path.display());
+      }
+    }
+  }
+}
+
+/// Built-in subscriber: maintains the resumable done-state and persists it
+/// atomically on every `Completed` event, writing to a temp file and
+/// renaming over `done.json` rather than truncating the live file in place.
+/// This removes the window where a crash mid-write corrupts the progress
+/// file a resumed run depends on.
+fn done_state_subscriber(rx: Receiver<ChunkEvent>, temp_dir: &Path) {
+  let done_path = temp_dir.join("done.json");
+  let tmp_path = temp_dir.join("done.json.tmp");
+  while let Ok(event) = rx.recv() {
+    if let ChunkEvent::Completed {
+      name,
+      encoded_frames,
+      ..
+    } = event
+    {
+      get_done().done.insert(name, encoded_frames);
+
+      if let Err(e) = write_done_state(&tmp_path, &done_path) {
+        warn!("Failed to persist {}: {e}", done_path.display());
+      }
+    }
+  }
+}
+
+/// Serializes the current done-state to `tmp_path` and atomically renames it
+/// over `done_path`, so a crash mid-write never leaves `done_path` truncated
+/// or holding a half-written file. A transient failure here is logged and
+/// skipped rather than unwrapped: the in-memory done-state already has the
+/// chunk recorded, so the next successful write catches it up.
+fn write_done_state(tmp_path: &Path, done_path: &Path) -> Result<(), String> {
+  let mut tmp_file = File::create(tmp_path).map_err(|e| e.to_string())?;
+  let serialized = serde_json::to_string(get_done()).map_err(|e| e.to_string())?;
+  tmp_file
+    .write_all(serialized.as_bytes())
+    .map_err(|e| e.to_string())?;
+  tmp_file.sync_all().map_err(|e| e.to_string())?;
+  fs::rename(tmp_path, done_path).map_err(|e| e.to_string())?;
+  Ok(())
+}
+
+/// Progressive, previewable output: as each chunk finishes (and passes its
+/// frame check), remux it into a fragmented segment and register it in a
+/// rolling manifest, so a viewer can watch the encode while later chunks
+/// are still running. Gated behind `--live-output <dir>` on `EncodeArgs`.
+struct SegmentMuxer {
+  live_dir: PathBuf,
+  /// Chunks that have been remuxed (or permanently given up on) but are
+  /// still waiting on an earlier chunk to land before they can be
+  /// accounted for in the manifest.
+  pending: Mutex<BTreeSet<usize>>,
+  /// Indices present in `pending` that were quarantined or failed to remux
+  /// rather than actually remuxed, so `advance_manifest` can skip past them instead of
+  /// referencing a segment file that doesn't exist.
+  skipped: Mutex<BTreeSet<usize>>,
+  /// The next chunk index the manifest needs in order to extend its
+  /// contiguous prefix.
+  next_index: AtomicUsize,
+}
+
+impl SegmentMuxer {
+  /// `start_index` is the lowest chunk index this run will actually
+  /// encode: on a resumed run, earlier chunks already finished in a
+  /// previous session and will never emit a `Completed` event here, so the
+  /// contiguous prefix must start from wherever this run picks up rather
+  /// than always from zero.
+  fn new(live_dir: PathBuf, start_index: usize) -> Self {
+    if let Err(e) = fs::create_dir_all(&live_dir) {
+      warn!("Live output: failed to create {}: {e}", live_dir.display());
+    }
+    Self {
+      live_dir,
+      pending: Mutex::new(BTreeSet::new()),
+      skipped: Mutex::new(BTreeSet::new()),
+      next_index: AtomicUsize::new(start_index),
+    }
+  }
+
+  fn segment_path(&self, index: usize) -> PathBuf {
+    self.live_dir.join(format!("segment_{index:05}.m4s"))
+  }
+
+  /// Remux one finished chunk and advance the manifest as far as the
+  /// now-contiguous run of completed chunks allows. Chunks that finish out
+  /// of order just sit in `pending` until the gap in front of them closes.
+  fn on_chunk_completed(&self, index: usize, source: &Path) {
+    let segment_path = self.segment_path(index);
+    if let Err(e) = remux_to_fragmented_segment(source, &segment_path) {
+      warn!("Live output: failed to remux chunk {index}: {e}");
+      // The chunk itself succeeded, so no `Quarantined` event is coming for
+      // it either; without marking it skipped here the manifest would wait
+      // forever for a segment that will never exist.
+      self.pending.lock().unwrap().insert(index);
+      self.skipped.lock().unwrap().insert(index);
+      self.advance_manifest();
+      return;
+    }
+
+    self.pending.lock().unwrap().insert(index);
+    self.advance_manifest();
+  }
+
+  /// A chunk that was quarantined after exhausting its retries will never
+  /// produce a `Completed` event; skip over it so the manifest doesn't
+  /// stall waiting for a segment that's never coming.
+  fn on_chunk_quarantined(&self, index: usize) {
+    self.pending.lock().unwrap().insert(index);
+    self.skipped.lock().unwrap().insert(index);
+    self.advance_manifest();
+  }
+
+  fn advance_manifest(&self) {
+    let mut pending = self.pending.lock().unwrap();
+    let skipped = self.skipped.lock().unwrap();
+    let mut newly_ready = Vec::new();
+    loop {
+      let next = self.next_index.load(Ordering::SeqCst);
+      if !pending.remove(&next) {
+        break;
+      }
+      if !skipped.contains(&next) {
+        newly_ready.push(next);
+      }
+      self.next_index.fetch_add(1, Ordering::SeqCst);
+    }
+    drop(pending);
+    drop(skipped);
+
+    if newly_ready.is_empty() {
+      return;
+    }
+
+    let manifest_path = self.live_dir.join("manifest.m3u8");
+    let is_new = !manifest_path.exists();
+    let mut manifest = match File::options().create(true).append(true).open(&manifest_path) {
+      Ok(file) => file,
+      Err(e) => {
+        warn!("Live output: failed to open {}: {e}", manifest_path.display());
+        return;
+      }
+    };
+    if is_new {
+      let _ = writeln!(manifest, "#EXTM3U\n#EXT-X-TARGETDURATION:10\n#EXT-X-PLAYLIST-TYPE:EVENT");
+    }
+    for index in newly_ready {
+      let _ = writeln!(manifest, "#EXTINF:10,\n{}", self.segment_path(index).display());
+    }
+  }
+}
+
+/// Remuxes `source` into a fragmented MP4 segment at `dest` without
+/// re-encoding, so it can be appended to a rolling HLS/DASH-style manifest.
+fn remux_to_fragmented_segment(source: &Path, dest: &Path) -> Result<(), String> {
+  let status = Command::new("ffmpeg")
+    .args(["-y", "-i"])
+    .arg(source)
+    .args(["-c", "copy", "-movflags", "frag_keyframe+empty_moov", "-f", "mp4"])
+    .arg(dest)
+    .status()
+    .map_err(|e| e.to_string())?;
+
+  if status.success() {
+    Ok(())
+  } else {
+    Err(format!("ffmpeg exited with {status}"))
+  }
+}
+
+/// Built-in subscriber: feeds every completed chunk into a [`SegmentMuxer`]
+/// so the live-output manifest advances as chunks finish, independent of
+/// the NDJSON log and done-state subscribers.
+fn live_output_subscriber(rx: Receiver<ChunkEvent>, muxer: Arc<SegmentMuxer>) {
+  while let Ok(event) = rx.recv() {
+    match event {
+      ChunkEvent::Completed { index, output, .. } => muxer.on_chunk_completed(index, &output),
+      ChunkEvent::Quarantined { index } => muxer.on_chunk_quarantined(index),
+      _ => {}
+    }
+  }
+}
+
+/// Controls the order chunks are dispatched to workers in, exposed as
+/// `--chunk-order` on `EncodeArgs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ChunkOrder {
+  /// Dispatch chunks in queue order (source order). The default.
+  Sequential,
+  /// Greedy Longest-Processing-Time-first: sort chunks by descending
+  /// estimated cost (frame count) and dispatch the heaviest first, so short
+  /// chunks backfill the tail instead of one worker running long after the
+  /// rest of the pool has gone idle.
+  Lpt,
+}
+
+/// Abstracts over `Instant::now()` so the worker-pool logic (timing-derived
+/// fps, elapsed-time bookkeeping) can be driven by a fake clock in tests
+/// instead of real wall-clock time.
+pub trait Clock: Send + Sync {
+  fn now(&self) -> Instant;
+}
+
+/// The real clock, backed by `std::time::Instant`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+  fn now(&self) -> Instant {
+    Instant::now()
+  }
+}
+
+/// Abstracts over actually running the encoder, so the retry/requeue and
+/// frame-check logic in `Broker` can be exercised with a mock backend
+/// instead of spawning real encoder processes.
+pub trait EncodeBackend: Send + Sync {
+  /// Run a single encoder pass for `chunk`, mirroring
+  /// `EncodeArgs::create_pipes`.
+  fn run_pass(
+    &self,
+    chunk: &Chunk,
+    pass: usize,
+    worker_id: usize,
+  ) -> Result<(), (ExitStatus, String)>;
+
+  /// Count the frames actually written to `path`, mirroring
+  /// `ffmpeg::num_frames`.
+  fn num_frames(&self, path: &Path) -> usize;
+
+  /// Pin the calling thread to the cores assigned to `worker_id` out of
+  /// `total_workers`, mirroring the `sched_setaffinity` call `encode_chunk`
+  /// used to make directly. A mock backend can no-op this, since affinity
+  /// has no observable effect on a fake encode.
+  fn assign_affinity(&self, worker_id: usize, total_workers: usize);
+}
+
+/// The real backend, delegating to `EncodeArgs::create_pipes` for encoding
+/// and to `ffmpeg` for frame counting.
+pub struct ProjectBackend<'a> {
+  project: &'a EncodeArgs,
+}
+
+impl<'a> EncodeBackend for ProjectBackend<'a> {
+  fn run_pass(
+    &self,
+    chunk: &Chunk,
+    pass: usize,
+    worker_id: usize,
+  ) -> Result<(), (ExitStatus, String)> {
+    self.project.create_pipes(chunk, pass, worker_id)
+  }
+
+  fn num_frames(&self, path: &Path) -> usize {
+    ffmpeg::num_frames(path).unwrap()
+  }
+
+  fn assign_affinity(&self, worker_id: usize, total_workers: usize) {
     // We assign in a round-robin fashion. Some cores may be shared if we have
     // a number of workers that is not divisible by the number of cores.
     //
@@ -79,18 +473,261 @@ impl<'a> Broker<'a> {
     // [1+9][1+9][2+10][2+10][3+11][3+11][4+12][4+12][5][5][6][6][7][7][8][8]
     // 16 workers, 8 cores
     // [1+9][2+10][3+11][4+12][5+13][6+14][7+15][8+16]
-    let cores_per_worker = (num_cpus::get() as f32 / self.project.workers as f32).ceil() as usize;
+    let cores_per_worker = (num_cpus::get() as f32 / total_workers as f32).ceil() as usize;
     let mut cpu_set = CpuSet::new();
     let start = worker_id * cores_per_worker;
     let end = start + cores_per_worker;
     for i in start..end {
-      cpu_set.set(i % self.project.workers).unwrap();
+      cpu_set.set(i % num_cpus::get()).unwrap();
     }
     sched_setaffinity(Pid::from_raw(0), &cpu_set).unwrap();
+  }
+}
+
+fn calc_fps(frames: usize, elapsed: Duration) -> f64 {
+  frames as f64 / elapsed.as_secs_f64()
+}
+
+/// What to do with a chunk that just failed another encode attempt.
+enum RetryOutcome {
+  /// Re-send the chunk after the given backoff; still below the global
+  /// retry limit.
+  Requeue(Duration),
+  /// The chunk has hit `max_retries` and should be quarantined.
+  Quarantine,
+}
 
-    let st_time = Instant::now();
+fn retry_outcome(attempt: u8, max_retries: u8) -> RetryOutcome {
+  if attempt < max_retries {
+    RetryOutcome::Requeue(Duration::from_secs(1 << attempt.min(6)))
+  } else {
+    RetryOutcome::Quarantine
+  }
+}
 
-    info!("Enc: {}, {} fr", chunk.index, chunk.frames);
+pub struct Broker<'a, C: Clock = SystemClock, B: EncodeBackend = ProjectBackend<'a>> {
+  pub chunk_queue: Vec<Chunk>,
+  pub project: &'a EncodeArgs,
+  pub target_quality: Option<TargetQuality<'a>>,
+  events: EventBus,
+  clock: C,
+  backend: B,
+}
+
+impl<'a> Broker<'a, SystemClock, ProjectBackend<'a>> {
+  pub fn new(
+    chunk_queue: Vec<Chunk>,
+    project: &'a EncodeArgs,
+    target_quality: Option<TargetQuality<'a>>,
+  ) -> Self {
+    Broker {
+      chunk_queue,
+      target_quality,
+      events: EventBus::new(),
+      clock: SystemClock,
+      backend: ProjectBackend { project },
+      project,
+    }
+  }
+}
+
+impl<'a, C: Clock, B: EncodeBackend> Broker<'a, C, B> {
+  /// Construct a broker with an injected clock and encode backend, e.g. for
+  /// tests that drive `encoding_loop` against a mock backend instead of
+  /// spawning real encoder processes.
+  pub fn with_backend(
+    chunk_queue: Vec<Chunk>,
+    project: &'a EncodeArgs,
+    target_quality: Option<TargetQuality<'a>>,
+    clock: C,
+    backend: B,
+  ) -> Self {
+    Broker {
+      chunk_queue,
+      project,
+      target_quality,
+      events: EventBus::new(),
+      clock,
+      backend,
+    }
+  }
+
+  /// `_tx` is kept for API compatibility with callers built against the
+  /// older contract where any message meant "a worker hit a fatal error,
+  /// abort the whole run." A quarantined chunk is, by design, not one of
+  /// those, so it's only reported through `ChunkEvent::Quarantined` and the
+  /// summary logged at the end of this function, and nothing is currently
+  /// sent on `_tx`.
+  #[allow(clippy::needless_pass_by_value)]
+  pub fn encoding_loop(mut self, _tx: Sender<()>, cmd_rx: Receiver<BrokerCommand>) {
+    if self.chunk_queue.is_empty() {
+      return;
+    }
+
+    // Dispatch order only affects which worker picks up which chunk when,
+    // not the index each chunk carries, so reordering here has no effect on
+    // how the finished chunks get concatenated afterwards.
+    if self.project.chunk_order == ChunkOrder::Lpt {
+      self
+        .chunk_queue
+        .sort_unstable_by_key(|chunk| std::cmp::Reverse(chunk.frames));
+    }
+
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    for chunk in &self.chunk_queue {
+      sender.send(chunk.clone()).unwrap();
+    }
+
+    // Tracks chunks that are either still sitting in the channel or actively
+    // being encoded by a worker. The pool only shuts down once this hits
+    // zero, so a re-queued chunk keeps the other workers alive.
+    let in_flight = Arc::new(AtomicUsize::new(self.chunk_queue.len()));
+    // Per-chunk attempt counts, shared across every worker so a chunk that
+    // keeps getting picked up by different workers still accumulates a
+    // single global retry count.
+    let attempts: Arc<DashMap<usize, u8>> = Arc::new(DashMap::new());
+    // Chunks that reached `max_chunk_retries` failures, reported once the rest of
+    // the encode has finished instead of tearing down the whole run.
+    let quarantined: Arc<DashMap<usize, String>> = Arc::new(DashMap::new());
+    let control = Arc::new(RunControl::new(self.project.workers));
+
+    crossbeam_utils::thread::scope(|s| {
+      let temp_dir = Path::new(&self.project.temp).to_path_buf();
+      let log_path = temp_dir.join("events.ndjson");
+      let log_rx = self.events.subscribe();
+      let done_rx = self.events.subscribe();
+      let log_subscriber = s.spawn(move |_| ndjson_log_subscriber(log_rx, &log_path));
+      let done_subscriber = s.spawn(move |_| done_state_subscriber(done_rx, &temp_dir));
+      let live_output_subscriber_handle = self.project.live_output.as_ref().map(|live_dir| {
+        let rx = self.events.subscribe();
+        // On a resumed run, chunks before this one already finished in a
+        // previous session and will never fire a `Completed` event here.
+        let start_index = self.chunk_queue.iter().map(|c| c.index).min().unwrap_or(0);
+        let muxer = Arc::new(SegmentMuxer::new(live_dir.clone(), start_index));
+        s.spawn(move |_| live_output_subscriber(rx, muxer))
+      });
+
+      let consumers: Vec<_> = (0..self.project.workers)
+        .map(|consumer_idx| {
+          let sender = sender.clone();
+          let receiver = receiver.clone();
+          let cmd_rx = cmd_rx.clone();
+          let in_flight = Arc::clone(&in_flight);
+          let attempts = Arc::clone(&attempts);
+          let quarantined = Arc::clone(&quarantined);
+          let control = Arc::clone(&control);
+          let queue = &self;
+          s.spawn(move |_| loop {
+            control.apply_pending(&cmd_rx, queue.project.workers);
+            control.park_if_inactive(consumer_idx, &cmd_rx, queue.project.workers);
+
+            if control.is_cancelled() {
+              // Drain whatever is left in the channel without encoding it so
+              // the pool can shut down promptly; `done.json` already only
+              // reflects chunks that actually finished, so the run stays
+              // resumable.
+              while let Ok(_chunk) = receiver.try_recv() {
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+              }
+              return;
+            }
+
+            let mut chunk = match receiver.recv_timeout(Duration::from_millis(100)) {
+              Ok(chunk) => chunk,
+              Err(RecvTimeoutError::Timeout) => {
+                if in_flight.load(Ordering::SeqCst) == 0 {
+                  return;
+                }
+                continue;
+              }
+              Err(RecvTimeoutError::Disconnected) => return,
+            };
+
+            match queue.encode_chunk(&mut chunk, consumer_idx) {
+              Ok(()) => {
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+              }
+              Err(output) => {
+                let attempt = {
+                  let mut entry = attempts.entry(chunk.index).or_insert(0);
+                  *entry += 1;
+                  *entry
+                };
+
+                queue.events.publish(ChunkEvent::Failed {
+                  index: chunk.index,
+                  attempt,
+                });
+
+                match retry_outcome(attempt, queue.project.max_chunk_retries) {
+                  RetryOutcome::Requeue(backoff) => {
+                    warn!(
+                      "Chunk {} failed (attempt {}/{}), re-queueing after {:?}",
+                      chunk.index, attempt, queue.project.max_chunk_retries, backoff
+                    );
+                    std::thread::sleep(backoff);
+                    queue.events.publish(ChunkEvent::Requeued { index: chunk.index });
+                    sender.send(chunk).unwrap();
+                  }
+                  RetryOutcome::Quarantine => {
+                    error!(
+                      "Chunk {} failed {} times, quarantining and continuing",
+                      chunk.index, queue.project.max_chunk_retries
+                    );
+                    quarantined.insert(chunk.index, output);
+                    queue.events.publish(ChunkEvent::Quarantined { index: chunk.index });
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                  }
+                }
+              }
+            }
+          })
+        })
+        .collect();
+      for consumer in consumers {
+        consumer.join().unwrap();
+      }
+
+      self.events.close();
+      log_subscriber.join().unwrap();
+      done_subscriber.join().unwrap();
+      if let Some(handle) = live_output_subscriber_handle {
+        handle.join().unwrap();
+      }
+    })
+    .unwrap();
+
+    if self.project.verbosity == Verbosity::Normal {
+      finish_progress_bar();
+    } else if self.project.verbosity == Verbosity::Verbose {
+      finish_multi_progress_bar();
+    }
+
+    if !quarantined.is_empty() {
+      error!(
+        "{} chunk(s) could not be encoded after {} attempts and were skipped:",
+        quarantined.len(),
+        self.project.max_chunk_retries
+      );
+      for entry in quarantined.iter() {
+        error!(
+          "  chunk {}:\n{}",
+          entry.key(),
+          textwrap::indent(entry.value(), "    ")
+        );
+      }
+    }
+  }
+
+  fn encode_chunk(&self, chunk: &mut Chunk, worker_id: usize) -> Result<(), String> {
+    self.backend.assign_affinity(worker_id, self.project.workers);
+
+    let st_time = self.clock.now();
+
+    self.events.publish(ChunkEvent::Started {
+      index: chunk.index,
+      frames: chunk.frames,
+    });
 
     if let Some(ref tq) = self.target_quality {
       tq.per_shot_target_quality_routine(chunk);
@@ -100,7 +737,7 @@ impl<'a> Broker<'a> {
     const MAX_TRIES: usize = 3;
     for current_pass in 1..=self.project.passes {
       for r#try in 1..=MAX_TRIES {
-        let res = self.project.create_pipes(chunk, current_pass, worker_id);
+        let res = self.backend.run_pass(chunk, current_pass, worker_id);
         if let Err((status, output)) = res {
           warn!(
             "Encoder failed (on chunk {}) with {}:\n{}",
@@ -116,48 +753,302 @@ impl<'a> Broker<'a> {
             return Err(output);
           }
         } else {
+          self.events.publish(ChunkEvent::PassDone {
+            index: chunk.index,
+            pass: current_pass,
+          });
           break;
         }
       }
     }
 
-    let encoded_frames = Self::frame_check_output(chunk, chunk.frames);
-
-    if encoded_frames == chunk.frames {
-      let progress_file = Path::new(&self.project.temp).join("done.json");
-      get_done().done.insert(chunk.name(), encoded_frames);
+    let encoded_frames = self.frame_check_output(chunk, chunk.frames);
 
-      let mut progress_file = File::create(&progress_file).unwrap();
-      progress_file
-        .write_all(serde_json::to_string(get_done()).unwrap().as_bytes())
-        .unwrap();
+    if encoded_frames != chunk.frames {
+      // A frame-count mismatch means the output can't be trusted even though
+      // the encoder exited cleanly; route it through the same retry/
+      // quarantine path as a crashed encoder instead of silently dropping
+      // the chunk, so it either gets re-encoded or reported as a failure.
+      return Err(format!(
+        "frame count mismatch: expected {}, got {encoded_frames}",
+        chunk.frames
+      ));
+    }
 
-      let enc_time = st_time.elapsed();
+    let enc_time = self.clock.now() - st_time;
 
-      info!(
-        "Done: {} Fr: {}/{}",
-        chunk.index, encoded_frames, chunk.frames
-      );
-      info!(
-        "Fps: {:.2} Time: {:?}",
-        encoded_frames as f64 / enc_time.as_secs_f64(),
-        enc_time
-      );
-    }
+    self.events.publish(ChunkEvent::Completed {
+      index: chunk.index,
+      name: chunk.name(),
+      output: chunk.output(),
+      encoded_frames,
+      fps: calc_fps(encoded_frames, enc_time),
+      elapsed_secs: enc_time.as_secs_f64(),
+    });
 
     Ok(())
   }
 
-  fn frame_check_output(chunk: &Chunk, expected_frames: usize) -> usize {
-    let actual_frames = ffmpeg::num_frames(chunk.output().as_ref()).unwrap();
+  fn frame_check_output(&self, chunk: &Chunk, expected_frames: usize) -> usize {
+    let actual_frames = self.backend.num_frames(chunk.output().as_ref());
 
     if actual_frames != expected_frames {
-      warn!(
-        "FRAME MISMATCH: Chunk #{}: {}/{} fr",
-        chunk.index, actual_frames, expected_frames
-      );
+      self.events.publish(ChunkEvent::FrameMismatch {
+        index: chunk.index,
+        expected: expected_frames,
+        actual: actual_frames,
+      });
     }
 
     actual_frames
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::{
+    collections::HashMap,
+    os::unix::process::ExitStatusExt,
+    sync::atomic::AtomicU64,
+  };
+
+  /// A clock that can be advanced by an arbitrary amount without actually
+  /// sleeping, so fps/elapsed-time assertions don't depend on real time.
+  struct MockClock {
+    base: Instant,
+    advance_nanos: AtomicU64,
+  }
+
+  impl MockClock {
+    fn new() -> Self {
+      Self {
+        base: Instant::now(),
+        advance_nanos: AtomicU64::new(0),
+      }
+    }
+
+    fn advance(&self, duration: Duration) {
+      self
+        .advance_nanos
+        .fetch_add(duration.as_nanos() as u64, Ordering::SeqCst);
+    }
+  }
+
+  impl Clock for MockClock {
+    fn now(&self) -> Instant {
+      self.base + Duration::from_nanos(self.advance_nanos.load(Ordering::SeqCst))
+    }
+  }
+
+  #[test]
+  fn mock_clock_advances_without_sleeping() {
+    let clock = MockClock::new();
+    let start = clock.now();
+    clock.advance(Duration::from_secs(5));
+    assert_eq!(clock.now() - start, Duration::from_secs(5));
+  }
+
+  #[test]
+  fn fps_is_frames_over_elapsed_seconds() {
+    assert!((calc_fps(300, Duration::from_secs(10)) - 30.0).abs() < f64::EPSILON);
+  }
+
+  #[test]
+  fn retries_until_the_configured_limit_then_quarantines() {
+    for attempt in 0..DEFAULT_MAX_CHUNK_RETRIES {
+      assert!(matches!(
+        retry_outcome(attempt, DEFAULT_MAX_CHUNK_RETRIES),
+        RetryOutcome::Requeue(_)
+      ));
+    }
+    assert!(matches!(
+      retry_outcome(DEFAULT_MAX_CHUNK_RETRIES, DEFAULT_MAX_CHUNK_RETRIES),
+      RetryOutcome::Quarantine
+    ));
+  }
+
+  #[test]
+  fn retry_limit_is_configurable_independent_of_the_default() {
+    assert!(matches!(retry_outcome(2, 3), RetryOutcome::Requeue(_)));
+    assert!(matches!(retry_outcome(3, 3), RetryOutcome::Quarantine));
+  }
+
+  #[test]
+  fn backoff_grows_exponentially_and_caps() {
+    let backoff = |attempt| match retry_outcome(attempt, DEFAULT_MAX_CHUNK_RETRIES) {
+      RetryOutcome::Requeue(backoff) => backoff,
+      RetryOutcome::Quarantine => panic!("expected a requeue"),
+    };
+
+    assert_eq!(backoff(0), Duration::from_secs(1));
+    assert_eq!(backoff(3), Duration::from_secs(8));
+    assert_eq!(backoff(20), Duration::from_secs(64)); // capped at 1 << 6
+  }
+
+  #[test]
+  fn park_if_inactive_drains_resume_while_parked() {
+    let control = RunControl::new(1);
+    let (cmd_tx, cmd_rx) = crossbeam_channel::unbounded();
+
+    cmd_tx.send(BrokerCommand::Pause).unwrap();
+    control.apply_pending(&cmd_rx, 1);
+    assert!(*control.paused.lock().unwrap());
+
+    // If `park_if_inactive` only checked `cmd_rx` via the outer worker loop
+    // (which this thread never returns to while parked) this would hang
+    // forever instead of observing the `Resume` queued below.
+    crossbeam_utils::thread::scope(|s| {
+      let handle = s.spawn(|_| control.park_if_inactive(0, &cmd_rx, 1));
+
+      std::thread::sleep(Duration::from_millis(200));
+      cmd_tx.send(BrokerCommand::Resume).unwrap();
+
+      handle.join().unwrap();
+    })
+    .unwrap();
+
+    assert!(!*control.paused.lock().unwrap());
+  }
+
+  #[test]
+  fn park_if_inactive_drains_cancel_while_parked() {
+    let control = RunControl::new(1);
+    let (cmd_tx, cmd_rx) = crossbeam_channel::unbounded();
+
+    cmd_tx.send(BrokerCommand::Pause).unwrap();
+    control.apply_pending(&cmd_rx, 1);
+
+    crossbeam_utils::thread::scope(|s| {
+      let handle = s.spawn(|_| control.park_if_inactive(0, &cmd_rx, 1));
+
+      std::thread::sleep(Duration::from_millis(200));
+      cmd_tx.send(BrokerCommand::Cancel).unwrap();
+
+      handle.join().unwrap();
+    })
+    .unwrap();
+
+    assert!(control.is_cancelled());
+  }
+
+  /// A backend that fails `run_pass` for a configured number of whole
+  /// attempts (each attempt being up to `MAX_TRIES` raw calls) per chunk
+  /// index, then succeeds, and reports a fixed frame count per chunk so
+  /// `frame_check_output` passes once `run_pass` does. Affinity assignment
+  /// is a no-op, since it has no observable effect on a fake encode.
+  struct MockBackend {
+    fail_attempts: HashMap<usize, u8>,
+    raw_calls: Mutex<HashMap<usize, u32>>,
+    reported_frames: HashMap<usize, usize>,
+  }
+
+  fn chunk_index_from_output(path: &Path) -> usize {
+    path
+      .file_stem()
+      .and_then(|s| s.to_str())
+      .and_then(|s| s.trim_start_matches("chunk_").parse().ok())
+      .expect("test chunk output paths are always named chunk_<index>")
+  }
+
+  impl EncodeBackend for MockBackend {
+    fn run_pass(
+      &self,
+      chunk: &Chunk,
+      _pass: usize,
+      _worker_id: usize,
+    ) -> Result<(), (ExitStatus, String)> {
+      let mut raw_calls = self.raw_calls.lock().unwrap();
+      let calls = raw_calls.entry(chunk.index).or_insert(0);
+      *calls += 1;
+
+      let fail_raw_calls = u32::from(self.fail_attempts.get(&chunk.index).copied().unwrap_or(0)) * 3;
+      if *calls <= fail_raw_calls {
+        Err((ExitStatus::from_raw(1), "mock encoder failure".to_string()))
+      } else {
+        Ok(())
+      }
+    }
+
+    fn num_frames(&self, path: &Path) -> usize {
+      let index = chunk_index_from_output(path);
+      self.reported_frames[&index]
+    }
+
+    fn assign_affinity(&self, _worker_id: usize, _total_workers: usize) {}
+  }
+
+  #[test]
+  fn encoding_loop_retries_then_quarantines_with_a_mock_backend() {
+    let temp = std::env::temp_dir().join(format!("av1an_broker_test_{}", std::process::id()));
+    fs::create_dir_all(&temp).unwrap();
+
+    let project = EncodeArgs {
+      workers: 2,
+      passes: 1,
+      verbosity: Verbosity::Normal,
+      temp: temp.clone(),
+      chunk_order: ChunkOrder::Sequential,
+      live_output: None,
+      max_chunk_retries: 2,
+    };
+
+    let chunks = vec![
+      // Always succeeds on the first try.
+      Chunk {
+        index: 0,
+        frames: 10,
+        temp: temp.clone(),
+      },
+      // Fails its first attempt, then succeeds on retry.
+      Chunk {
+        index: 1,
+        frames: 10,
+        temp: temp.clone(),
+      },
+      // Never succeeds: exhausts the retry limit and gets quarantined.
+      Chunk {
+        index: 2,
+        frames: 10,
+        temp: temp.clone(),
+      },
+    ];
+
+    let backend = MockBackend {
+      fail_attempts: HashMap::from([(1, 1), (2, u8::MAX)]),
+      raw_calls: Mutex::new(HashMap::new()),
+      reported_frames: HashMap::from([(0, 10), (1, 10), (2, 10)]),
+    };
+
+    let broker = Broker::with_backend(chunks, &project, None, MockClock::new(), backend);
+    let event_rx = broker.events.subscribe();
+
+    let (tx, _rx) = std::sync::mpsc::channel();
+    let (_cmd_tx, cmd_rx) = crossbeam_channel::unbounded();
+    broker.encoding_loop(tx, cmd_rx);
+
+    let events: Vec<ChunkEvent> = event_rx.try_iter().collect();
+    let completed: Vec<usize> = events
+      .iter()
+      .filter_map(|e| match e {
+        ChunkEvent::Completed { index, .. } => Some(*index),
+        _ => None,
+      })
+      .collect();
+
+    assert!(completed.contains(&0), "chunk 0 should complete immediately");
+    assert!(completed.contains(&1), "chunk 1 should complete after a retry");
+    assert!(!completed.contains(&2), "chunk 2 should never complete");
+
+    assert!(
+      events.iter().any(|e| matches!(e, ChunkEvent::Requeued { index: 1 })),
+      "chunk 1 should have been requeued after its first failure"
+    );
+    assert!(
+      events.iter().any(|e| matches!(e, ChunkEvent::Quarantined { index: 2 })),
+      "chunk 2 should have been quarantined after exhausting its retries"
+    );
+
+    fs::remove_dir_all(&temp).ok();
+  }
+}
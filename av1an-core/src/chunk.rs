@@ -0,0 +1,22 @@
+use std::path::PathBuf;
+
+/// One source segment to be encoded independently and later concatenated
+/// back together in its original order. `index` is the chunk's position in
+/// that final concatenation, not necessarily the order it gets dispatched
+/// to a worker in (see `broker::ChunkOrder`).
+#[derive(Debug, Clone)]
+pub struct Chunk {
+  pub index: usize,
+  pub frames: usize,
+  pub temp: PathBuf,
+}
+
+impl Chunk {
+  pub fn name(&self) -> String {
+    format!("chunk_{:05}", self.index)
+  }
+
+  pub fn output(&self) -> PathBuf {
+    self.temp.join(format!("{}.mkv", self.name()))
+  }
+}
@@ -0,0 +1,76 @@
+use std::{
+  os::unix::process::ExitStatusExt,
+  path::PathBuf,
+  process::{Command, ExitStatus},
+};
+
+use clap::Args;
+
+use crate::{
+  broker::{ChunkOrder, DEFAULT_MAX_CHUNK_RETRIES},
+  Chunk, Verbosity,
+};
+
+/// Parameters for a single encode run. Parsed from the CLI and then threaded
+/// by reference through the chunking/encoding pipeline, rather than cloned
+/// into every piece of code that needs a setting.
+#[derive(Args, Debug, Clone)]
+pub struct EncodeArgs {
+  /// Number of worker processes to run in parallel.
+  #[clap(short, long, default_value_t = num_cpus::get())]
+  pub workers: usize,
+
+  /// Number of encoder passes to run per chunk.
+  #[clap(long, default_value_t = 1)]
+  pub passes: usize,
+
+  #[clap(skip)]
+  pub verbosity: Verbosity,
+
+  /// Temporary directory used for chunks, logs, and resumable state.
+  #[clap(long)]
+  pub temp: PathBuf,
+
+  /// Order chunks are dispatched to workers in.
+  #[clap(long, value_enum, default_value_t = ChunkOrder::Sequential)]
+  pub chunk_order: ChunkOrder,
+
+  /// Directory to write a rolling, previewable manifest and fragmented
+  /// segments to as chunks finish, instead of waiting for the whole encode
+  /// to concatenate output.
+  #[clap(long)]
+  pub live_output: Option<PathBuf>,
+
+  /// Number of failed attempts after which a single chunk is quarantined and
+  /// reported as a failure at the end of the run, rather than aborting the
+  /// whole encode. Every earlier failure re-queues the chunk.
+  #[clap(long, default_value_t = DEFAULT_MAX_CHUNK_RETRIES)]
+  pub max_chunk_retries: u8,
+}
+
+impl EncodeArgs {
+  /// Runs `aomenc` for one pass over `chunk`, writing the pass's output to
+  /// `chunk.output()`.
+  pub fn create_pipes(
+    &self,
+    chunk: &Chunk,
+    current_pass: usize,
+    worker_id: usize,
+  ) -> Result<(), (ExitStatus, String)> {
+    let status = Command::new("aomenc")
+      .arg("--pass")
+      .arg(current_pass.to_string())
+      .arg("--worker")
+      .arg(worker_id.to_string())
+      .arg("-o")
+      .arg(chunk.output().as_ref())
+      .status()
+      .map_err(|e| (ExitStatus::from_raw(-1), e.to_string()))?;
+
+    if status.success() {
+      Ok(())
+    } else {
+      Err((status, format!("encoder exited with {status}")))
+    }
+  }
+}